@@ -1,9 +1,12 @@
 // Copyright 2018-2022 the Deno authors. All rights reserved. MIT license.
 
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::io::ErrorKind;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use deno_ast::ModuleSpecifier;
 use deno_core::error::AnyError;
@@ -11,6 +14,7 @@ use deno_core::futures;
 use deno_core::futures::future::BoxFuture;
 use deno_core::url::Url;
 use deno_runtime::deno_node::NodeResolutionMode;
+use tokio::sync::Semaphore;
 
 use crate::args::Lockfile;
 use crate::npm::cache::should_sync_download;
@@ -20,6 +24,26 @@ use crate::npm::NpmPackageId;
 use crate::npm::NpmPackageReq;
 use crate::npm::NpmResolutionPackage;
 
+/// Default number of packages that may be downloaded at the same time when
+/// caching, unless overridden by `--npm-concurrency` or `NPM_CONCURRENCY`.
+const DEFAULT_NPM_CONCURRENCY: usize = 12;
+
+/// Reads the configured npm download concurrency from the environment.
+///
+/// This is consulted by callers that build the `--npm-concurrency` flag
+/// default; `cache_packages` itself just takes the resolved number so it
+/// doesn't need to know about flags or env vars.
+pub fn npm_concurrency_from_env() -> usize {
+  match std::env::var("NPM_CONCURRENCY") {
+    Ok(value) => value
+      .parse::<usize>()
+      .ok()
+      .filter(|n| *n > 0)
+      .unwrap_or(DEFAULT_NPM_CONCURRENCY),
+    Err(_) => DEFAULT_NPM_CONCURRENCY,
+  }
+}
+
 pub trait InnerNpmPackageResolver: Send + Sync {
   fn resolve_package_folder_from_deno_module(
     &self,
@@ -56,15 +80,425 @@ pub trait InnerNpmPackageResolver: Send + Sync {
 
   fn snapshot(&self) -> NpmResolutionSnapshot;
 
+  /// Persists this resolver's packages into the lockfile, including the
+  /// integrity hash [`cache_packages`] verified for each tarball against
+  /// its actual bytes (not merely the `dist.integrity` the registry
+  /// reported). Once locked, future installs of this package check the
+  /// tarball against that locked value instead of trusting the registry's
+  /// metadata again — see [`cache_packages`]'s `locked_integrities`
+  /// parameter.
   fn lock(&self, lockfile: &mut Lockfile) -> Result<(), AnyError>;
 }
 
-/// Caches all the packages in parallel.
+/// Verifies a downloaded tarball's bytes against the `integrity` (or legacy
+/// `shasum`) string from `package.dist`, in the same `<algorithm>-<base64>`
+/// format npm itself uses (see the `ssri` package). Callers should run this
+/// before a package is accepted into the cache, and treat a mismatch as a
+/// fatal, non-retryable error distinct from a plain download failure.
+pub fn verify_tarball_integrity(
+  package_id: &NpmPackageId,
+  tarball_bytes: &[u8],
+  integrity: &str,
+) -> Result<(), AnyError> {
+  let (algorithm_name, expected_base64) =
+    integrity.split_once('-').ok_or_else(|| {
+      deno_core::error::custom_error(
+        "IntegrityCheckFailed",
+        format!(
+          "Could not parse integrity '{}' for package {}",
+          integrity, package_id
+        ),
+      )
+    })?;
+  let algorithm = match algorithm_name {
+    "sha1" => &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+    "sha512" => &ring::digest::SHA512,
+    _ => {
+      return Err(deno_core::error::custom_error(
+        "IntegrityCheckFailed",
+        format!(
+          "Unsupported integrity algorithm '{}' for package {}",
+          algorithm_name, package_id
+        ),
+      ))
+    }
+  };
+  let actual_base64 =
+    base64::encode(ring::digest::digest(algorithm, tarball_bytes));
+  if actual_base64 != expected_base64 {
+    return Err(deno_core::error::custom_error(
+      "IntegrityCheckFailed",
+      format!(
+        "Integrity check failed for package: {}\n\nExpected: {}\nActual: {}-{}",
+        package_id, integrity, algorithm_name, actual_base64
+      ),
+    ));
+  }
+  Ok(())
+}
+
+/// Default number of times a single package download is attempted before
+/// giving up, unless overridden by `NPM_CONFIG_FETCH_RETRIES`.
+const DEFAULT_FETCH_RETRIES: u32 = 3;
+
+/// Default delay before the first retry, unless overridden by
+/// `NPM_CONFIG_FETCH_RETRY_MINTIMEOUT`. Doubles on each subsequent attempt.
+const DEFAULT_FETCH_RETRY_MIN_TIMEOUT_MS: u64 = 500;
+
+/// Controls how `cache_packages` retries a failed package download.
+///
+/// Mirrors npm's own `fetch-retries` / `fetch-retry-mintimeout` config so
+/// it's tunable from CI without code changes.
+#[derive(Debug, Clone, Copy)]
+pub struct NpmFetchRetryConfig {
+  pub max_attempts: u32,
+  pub min_timeout: Duration,
+}
+
+impl Default for NpmFetchRetryConfig {
+  fn default() -> Self {
+    Self::from_env()
+  }
+}
+
+impl NpmFetchRetryConfig {
+  pub fn from_env() -> Self {
+    let max_attempts = std::env::var("NPM_CONFIG_FETCH_RETRIES")
+      .ok()
+      .and_then(|v| v.parse().ok())
+      .filter(|n| *n > 0)
+      .unwrap_or(DEFAULT_FETCH_RETRIES);
+    let min_timeout_ms = std::env::var("NPM_CONFIG_FETCH_RETRY_MINTIMEOUT")
+      .ok()
+      .and_then(|v| v.parse().ok())
+      .unwrap_or(DEFAULT_FETCH_RETRY_MIN_TIMEOUT_MS);
+    Self {
+      max_attempts,
+      min_timeout: Duration::from_millis(min_timeout_ms),
+    }
+  }
+}
+
+/// Returns `true` only for error classes that are actually transient:
+/// connection-level failures, 429 (rate limited), and 5xx responses. Everyone
+/// else fails fast instead of burning through retries on an error that won't
+/// go away — a missing package (404), an auth failure (401/403), a bad
+/// integrity check, or a local I/O error from `store_tarball` (e.g. disk
+/// full) are all non-retryable.
+fn is_retryable_fetch_error(err: &AnyError) -> bool {
+  matches!(
+    deno_core::error::get_custom_error_class(err),
+    Some("ConnectionReset")
+      | Some("ConnectionAborted")
+      | Some("ConnectionRefused")
+      | Some("TimedOut")
+      | Some("Http429")
+      | Some("Http5xx")
+  )
+}
+
+/// Downloads a package's tarball and verifies it with
+/// [`verify_tarball_integrity`] before handing the bytes to `cache` to be
+/// written into the local npm cache. `expected_integrity` is the *locked*
+/// integrity from a previous install when one exists, and only falls back
+/// to the freshly-fetched `package.dist.integrity` on a first install —
+/// see [`cache_packages`]. A mismatch is a fatal error, not a plain
+/// download failure — see [`is_retryable_fetch_error`].
+async fn fetch_and_store_package(
+  cache: &NpmCache,
+  package: &NpmResolutionPackage,
+  registry: &Url,
+  auth_token: Option<&str>,
+  expected_integrity: &str,
+) -> Result<(), AnyError> {
+  let tarball_bytes: Vec<u8> = cache
+    .download_tarball(
+      (package.id.name.as_str(), &package.id.version),
+      &package.dist,
+      registry,
+      auth_token,
+    )
+    .await?;
+  verify_tarball_integrity(&package.id, &tarball_bytes, expected_integrity)?;
+  cache
+    .store_tarball(
+      (package.id.name.as_str(), &package.id.version),
+      &tarball_bytes,
+    )
+    .await
+}
+
+/// Calls [`fetch_and_store_package`] using the registry + auth token
+/// selected for the package's scope, retrying retryable failures with
+/// exponential backoff and jitter up to `retry_config.max_attempts` times.
+/// The final error is returned unchanged once attempts are exhausted. On
+/// success, returns the integrity value the tarball was verified against,
+/// so the caller can record it in the lockfile.
+async fn ensure_package_with_retry(
+  cache: &NpmCache,
+  package: &NpmResolutionPackage,
+  registry_config: &NpmRegistryConfig,
+  retry_config: &NpmFetchRetryConfig,
+  expected_integrity: &str,
+) -> Result<String, AnyError> {
+  let registries: Vec<&Url> = registry_config.registries_in_order().collect();
+  let mut attempt = 0;
+  loop {
+    let mut last_err = None;
+    for registry in &registries {
+      let result = fetch_and_store_package(
+        cache,
+        package,
+        registry,
+        registry_config.auth_token.as_deref(),
+        expected_integrity,
+      )
+      .await;
+      match result {
+        // the tarball is stored by name and version only; which registry
+        // served it is not recorded, and the next package again starts
+        // from the primary registry
+        Ok(()) => return Ok(expected_integrity.to_string()),
+        Err(err) if is_retryable_fetch_error(&err) => {
+          last_err = Some(err); // fall through to the next mirror
+        }
+        Err(err) => return Err(err), // fatal error, mirrors won't help
+      }
+    }
+    attempt += 1;
+    let err = last_err.expect("at least one registry was tried");
+    if attempt >= retry_config.max_attempts {
+      return Err(err);
+    }
+    let exponent = (attempt - 1).min(20); // cap so 2u32.pow() can't overflow
+    let backoff = retry_config.min_timeout * 2u32.pow(exponent);
+    let jitter = Duration::from_millis(rand::random::<u64>() % 250);
+    tokio::time::sleep(backoff + jitter).await;
+  }
+}
+
+/// A registry URL plus the bearer token to send with requests to it, as
+/// configured by `.npmrc` (`_authToken`/`_auth`) or the matching
+/// `NPM_CONFIG_*` environment variable. `mirrors` are tried, in order,
+/// after `registry` if a fetch against it fails with a retryable error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NpmRegistryConfig {
+  pub registry: Url,
+  pub auth_token: Option<String>,
+  pub mirrors: Vec<Url>,
+}
+
+impl NpmRegistryConfig {
+  /// The registries to try for a fetch, in order: the primary registry
+  /// first, then each configured mirror.
+  pub fn registries_in_order(&self) -> impl Iterator<Item = &Url> {
+    std::iter::once(&self.registry).chain(self.mirrors.iter())
+  }
+}
+
+/// Per-scope registry and auth config parsed from `.npmrc` and
+/// `NPM_CONFIG_*` env vars, so installs can mix public and private
+/// (enterprise/scoped) registries in one tree.
+#[derive(Debug, Clone)]
+pub struct NpmRegistryConfigs {
+  default: NpmRegistryConfig,
+  scopes: HashMap<String, NpmRegistryConfig>,
+}
+
+impl NpmRegistryConfigs {
+  pub fn new(default_registry: Url) -> Self {
+    Self {
+      default: NpmRegistryConfig {
+        registry: default_registry,
+        auth_token: None,
+        mirrors: Vec::new(),
+      },
+      scopes: HashMap::new(),
+    }
+  }
+
+  /// Parses an `.npmrc` file's contents, then applies any `NPM_CONFIG_*`
+  /// environment variable overrides on top (env vars win, matching npm's
+  /// own config precedence).
+  pub fn from_npmrc(default_registry: Url, npmrc_contents: &str) -> Self {
+    let mut configs = Self::new(default_registry);
+    // `//host/:_authToken=...` lines are keyed by registry host, not by
+    // scope name, so they're collected separately and matched up against
+    // the registry each scope (or the default) ends up pointing at below.
+    let mut host_auth_tokens: HashMap<String, String> = HashMap::new();
+    for line in npmrc_contents.lines() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+        continue;
+      }
+      let Some((key, value)) = line.split_once('=') else {
+        continue;
+      };
+      let key = key.trim();
+      let value = expand_env_vars(value.trim());
+      if let Some(scope) = key.strip_suffix(":registry").and_then(|k| {
+        k.strip_prefix('@')
+      }) {
+        if let Ok(url) = Url::parse(&value) {
+          configs.scope_mut(scope).registry = url;
+        }
+      } else if key == "registry" {
+        if let Ok(url) = Url::parse(&value) {
+          configs.default.registry = url;
+        }
+      } else if key == "_authToken" || key == "_auth" {
+        configs.default.auth_token = Some(value);
+      } else if key == "registry-mirrors" {
+        configs.default.mirrors = parse_registry_list(&value);
+      } else if let Some(registry_host) = key
+        .strip_prefix("//")
+        .and_then(|k| k.strip_suffix(":_authToken"))
+      {
+        host_auth_tokens
+          .insert(registry_host.trim_end_matches('/').to_string(), value);
+      }
+    }
+    configs.apply_env_overrides();
+    configs.apply_host_auth_tokens(&host_auth_tokens);
+    configs
+  }
+
+  /// Fills in `auth_token` for the default registry and every scope whose
+  /// registry host has a matching `//host/:_authToken` entry. A token set
+  /// directly via a bare `_authToken`/`_auth` line is left alone.
+  fn apply_host_auth_tokens(
+    &mut self,
+    host_auth_tokens: &HashMap<String, String>,
+  ) {
+    for config in
+      std::iter::once(&mut self.default).chain(self.scopes.values_mut())
+    {
+      if config.auth_token.is_some() {
+        continue;
+      }
+      if let Some(host) = config.registry.host_str() {
+        if let Some(token) = host_auth_tokens.get(host) {
+          config.auth_token = Some(token.clone());
+        }
+      }
+    }
+  }
+
+  fn apply_env_overrides(&mut self) {
+    if let Ok(registry) = std::env::var("NPM_CONFIG_REGISTRY") {
+      if let Ok(url) = Url::parse(&registry) {
+        self.default.registry = url;
+      }
+    }
+    if let Ok(token) = std::env::var("NPM_CONFIG__AUTHTOKEN") {
+      self.default.auth_token = Some(token);
+    }
+    if let Ok(mirrors) = std::env::var("NPM_CONFIG_REGISTRY_MIRRORS") {
+      self.default.mirrors = parse_registry_list(&mirrors);
+    }
+  }
+
+  fn scope_mut(&mut self, scope: &str) -> &mut NpmRegistryConfig {
+    self.scopes.entry(scope.to_string()).or_insert_with(|| {
+      NpmRegistryConfig {
+        registry: self.default.registry.clone(),
+        auth_token: None,
+        mirrors: Vec::new(),
+      }
+    })
+  }
+
+  /// Selects the registry + auth token to use for a package, based on its
+  /// scope (the `@scope` in `@scope/name`) if it has one.
+  pub fn resolve_for_package(&self, package_name: &str) -> &NpmRegistryConfig {
+    if let Some(scope) = package_name
+      .strip_prefix('@')
+      .and_then(|rest| rest.split('/').next())
+    {
+      if let Some(config) = self.scopes.get(scope) {
+        return config;
+      }
+    }
+    &self.default
+  }
+}
+
+/// Expands `${VAR_NAME}` references against the process environment, the
+/// way `.npmrc` does for things like `_authToken=${NPM_TOKEN}`.
+fn expand_env_vars(value: &str) -> String {
+  let mut result = String::with_capacity(value.len());
+  let mut rest = value;
+  while let Some(start) = rest.find("${") {
+    let Some(end) = rest[start..].find('}') else {
+      break;
+    };
+    result.push_str(&rest[..start]);
+    let var_name = &rest[start + 2..start + end];
+    if let Ok(var_value) = std::env::var(var_name) {
+      result.push_str(&var_value);
+    }
+    rest = &rest[start + end + 1..];
+  }
+  result.push_str(rest);
+  result
+}
+
+/// Parses a comma-separated list of mirror registry URLs, e.g. from
+/// `registry-mirrors=...` in `.npmrc` or `NPM_CONFIG_REGISTRY_MIRRORS`.
+/// Entries that don't parse as URLs are skipped.
+fn parse_registry_list(value: &str) -> Vec<Url> {
+  value
+    .split(',')
+    .map(|s| s.trim())
+    .filter(|s| !s.is_empty())
+    .filter_map(|s| Url::parse(s).ok())
+    .collect()
+}
+
+/// Whether `cache_packages` is allowed to contact the npm registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NpmCacheSetting {
+  /// Download any package that isn't already cached.
+  Use,
+  /// Never contact the registry; resolution relies entirely on what's
+  /// already present in the local npm cache. Used for `--cached-only` and
+  /// other reproducible/air-gapped builds.
+  Only,
+}
+
+impl Default for NpmCacheSetting {
+  fn default() -> Self {
+    Self::Use
+  }
+}
+
+/// Caches all the packages in parallel, bounded by `concurrency` simultaneous
+/// downloads so that a large dependency tree doesn't open hundreds of
+/// connections to the registry at once. Each package's tarball is verified
+/// with [`verify_tarball_integrity`] (see [`fetch_and_store_package`])
+/// before it's accepted into the cache.
+///
+/// `locked_integrities` maps a package's [`NpmPackageId`] (by its `Display`
+/// string) to the integrity hash a *previous* install locked in for it. When
+/// a package has an entry, its tarball is checked against that locked value
+/// instead of the registry's freshly-fetched `dist.integrity` — this is what
+/// protects against a compromised registry serving a tarball that's merely
+/// self-consistent with bad metadata. Pass an empty map when there's no
+/// lockfile yet (e.g. the very first install).
+///
+/// Returns the integrity value each package was verified against, keyed the
+/// same way, so the caller can record it in the lockfile.
+/// [`InnerNpmPackageResolver::lock`] takes only the lockfile and is not
+/// given this map.
 pub async fn cache_packages(
   mut packages: Vec<NpmResolutionPackage>,
   cache: &NpmCache,
-  registry_url: &Url,
-) -> Result<(), AnyError> {
+  registry_configs: &NpmRegistryConfigs,
+  concurrency: usize,
+  retry_config: NpmFetchRetryConfig,
+  cache_setting: NpmCacheSetting,
+  locked_integrities: &HashMap<String, String>,
+) -> Result<HashMap<String, String>, AnyError> {
   let sync_download = should_sync_download();
   if sync_download {
     // we're running the tests not with --quiet
@@ -72,22 +506,59 @@ pub async fn cache_packages(
     packages.sort_by(|a, b| a.id.cmp(&b.id));
   }
 
+  if cache_setting == NpmCacheSetting::Only {
+    for package in &packages {
+      if !cache.package_folder_exists(&package.id) {
+        return Err(deno_core::error::custom_error(
+          "NotCached",
+          format!(
+            "Could not find cached npm package \"{}\" and --cached-only is set. \
+             Run without --cached-only to allow downloading it.",
+            package.id
+          ),
+        ));
+      }
+    }
+    // nothing was downloaded, so there's nothing new to lock; the existing
+    // lockfile entries (if any) are still accurate.
+    return Ok(HashMap::new());
+  }
+
+  let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
   let mut handles = Vec::with_capacity(packages.len());
+  let mut verified_integrities = HashMap::with_capacity(packages.len());
   for package in packages {
     assert_eq!(package.copy_index, 0); // the caller should not provide any of these
     let cache = cache.clone();
-    let registry_url = registry_url.clone();
+    let registry_config =
+      registry_configs.resolve_for_package(&package.id.name).clone();
+    let semaphore = semaphore.clone();
+    let retry_config = retry_config;
+    let package_id = package.id.to_string();
+    let expected_integrity = locked_integrities
+      .get(&package_id)
+      .cloned()
+      .unwrap_or_else(|| package.dist.integrity.clone());
     let handle = tokio::task::spawn(async move {
-      cache
-        .ensure_package(
-          (package.id.name.as_str(), &package.id.version),
-          &package.dist,
-          &registry_url,
-        )
+      // hold the permit for the duration of the download so that at most
+      // `concurrency` tasks are ever fetching from the registry at once
+      let _permit = semaphore
+        .acquire_owned()
         .await
+        .expect("semaphore should not be closed");
+      let integrity = ensure_package_with_retry(
+        &cache,
+        &package,
+        &registry_config,
+        &retry_config,
+        &expected_integrity,
+      )
+      .await?;
+      Ok::<_, AnyError>((package_id, integrity))
     });
     if sync_download {
-      handle.await??;
+      let (package_id, integrity) = handle.await??;
+      verified_integrities.insert(package_id, integrity);
     } else {
       handles.push(handle);
     }
@@ -95,9 +566,10 @@ pub async fn cache_packages(
   let results = futures::future::join_all(handles).await;
   for result in results {
     // surface the first error
-    result??;
+    let (package_id, integrity) = result??;
+    verified_integrities.insert(package_id, integrity);
   }
-  Ok(())
+  Ok(verified_integrities)
 }
 
 pub fn ensure_registry_read_permission(
@@ -140,7 +612,18 @@ pub fn types_package_name(package_name: &str) -> String {
 
 #[cfg(test)]
 mod test {
+  use super::expand_env_vars;
+  use super::is_retryable_fetch_error;
+  use super::parse_registry_list;
   use super::types_package_name;
+  use super::verify_tarball_integrity;
+  use super::NpmPackageId;
+  use super::NpmRegistryConfigs;
+  use super::Url;
+
+  fn test_package_id() -> NpmPackageId {
+    NpmPackageId::from_serialized("test-package@1.0.0").unwrap()
+  }
 
   #[test]
   fn test_types_package_name() {
@@ -150,4 +633,165 @@ mod test {
       "@types/@scoped__package"
     );
   }
+
+  #[test]
+  fn test_expand_env_vars() {
+    std::env::set_var("DENO_TEST_NPMRC_TOKEN", "secret123");
+    assert_eq!(expand_env_vars("${DENO_TEST_NPMRC_TOKEN}"), "secret123");
+    assert_eq!(
+      expand_env_vars("pre-${DENO_TEST_NPMRC_TOKEN}-post"),
+      "pre-secret123-post"
+    );
+    assert_eq!(expand_env_vars("no vars here"), "no vars here");
+    std::env::remove_var("DENO_TEST_NPMRC_TOKEN");
+  }
+
+  #[test]
+  fn test_parse_registry_list() {
+    let urls = parse_registry_list(
+      "https://a.example/, https://b.example/,not-a-url, https://c.example/",
+    );
+    assert_eq!(
+      urls.iter().map(|u| u.as_str()).collect::<Vec<_>>(),
+      vec![
+        "https://a.example/",
+        "https://b.example/",
+        "https://c.example/"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_npmrc_scoped_registry_picks_up_host_auth_token() {
+    // the standard private-registry pattern: a scope points at a private
+    // registry, and a separate line gives the token for that host
+    let npmrc = "@myscope:registry=https://private.example/\n\
+      //private.example/:_authToken=scoped-secret\n";
+    let configs = NpmRegistryConfigs::from_npmrc(
+      Url::parse("https://registry.npmjs.org/").unwrap(),
+      npmrc,
+    );
+
+    let scoped = configs.resolve_for_package("@myscope/pkg");
+    assert_eq!(scoped.registry.as_str(), "https://private.example/");
+    assert_eq!(scoped.auth_token.as_deref(), Some("scoped-secret"));
+
+    // unscoped packages aren't sent the private registry's token
+    let default = configs.resolve_for_package("left-pad");
+    assert_eq!(default.registry.as_str(), "https://registry.npmjs.org/");
+    assert_eq!(default.auth_token, None);
+  }
+
+  #[test]
+  fn test_npmrc_default_registry_auth_token_with_env_expansion() {
+    std::env::set_var("DENO_TEST_NPMRC_TOKEN2", "env-secret");
+    let npmrc = "//registry.npmjs.org/:_authToken=${DENO_TEST_NPMRC_TOKEN2}\n";
+    let configs = NpmRegistryConfigs::from_npmrc(
+      Url::parse("https://registry.npmjs.org/").unwrap(),
+      npmrc,
+    );
+    assert_eq!(
+      configs.resolve_for_package("left-pad").auth_token.as_deref(),
+      Some("env-secret")
+    );
+    std::env::remove_var("DENO_TEST_NPMRC_TOKEN2");
+  }
+
+  #[test]
+  fn test_npmrc_bare_auth_token_not_overridden_by_host_token() {
+    let npmrc = "_authToken=bare-token\n\
+      //registry.npmjs.org/:_authToken=host-token\n";
+    let configs = NpmRegistryConfigs::from_npmrc(
+      Url::parse("https://registry.npmjs.org/").unwrap(),
+      npmrc,
+    );
+    assert_eq!(
+      configs.resolve_for_package("left-pad").auth_token.as_deref(),
+      Some("bare-token")
+    );
+  }
+
+  #[test]
+  fn test_verify_tarball_integrity_sha512_match() {
+    let package_id = test_package_id();
+    let integrity = "sha512-MJ7MSJwS1utMxA9QyQLytNDtd+5RGnx6m808qG1M2G+YndNbxf9JlnDaNCVbRbDP2DDoH2Bdz33FVC6TrpzXbw==";
+    assert!(
+      verify_tarball_integrity(&package_id, b"hello world", integrity).is_ok()
+    );
+  }
+
+  #[test]
+  fn test_verify_tarball_integrity_sha1_match() {
+    let package_id = test_package_id();
+    let integrity = "sha1-Kq5sNclPz7QV2+lfQIuc6R7oRu0=";
+    assert!(
+      verify_tarball_integrity(&package_id, b"hello world", integrity).is_ok()
+    );
+  }
+
+  #[test]
+  fn test_verify_tarball_integrity_mismatch() {
+    let package_id = test_package_id();
+    // well-formed sha512 integrity string, but for different bytes
+    let integrity = "sha512-z4PhNX7vuL3xVChQ1m2AB9Yg5AULVxXcg/SpIdNs6c5H0NE8XYXysP+DGNKHfuwvY7kxvUdBeoGlODJ6+SfaPg==";
+    let err = verify_tarball_integrity(&package_id, b"hello world", integrity)
+      .unwrap_err();
+    assert_eq!(
+      deno_core::error::get_custom_error_class(&err),
+      Some("IntegrityCheckFailed")
+    );
+  }
+
+  #[test]
+  fn test_verify_tarball_integrity_unsupported_algorithm() {
+    let package_id = test_package_id();
+    let err = verify_tarball_integrity(&package_id, b"hello world", "md5-deadbeef")
+      .unwrap_err();
+    assert_eq!(
+      deno_core::error::get_custom_error_class(&err),
+      Some("IntegrityCheckFailed")
+    );
+  }
+
+  #[test]
+  fn test_verify_tarball_integrity_malformed() {
+    let package_id = test_package_id();
+    let err =
+      verify_tarball_integrity(&package_id, b"hello world", "not an integrity string")
+        .unwrap_err();
+    assert_eq!(
+      deno_core::error::get_custom_error_class(&err),
+      Some("IntegrityCheckFailed")
+    );
+  }
+
+  #[test]
+  fn test_is_retryable_fetch_error() {
+    assert!(is_retryable_fetch_error(&deno_core::error::custom_error(
+      "Http5xx",
+      "server error"
+    )));
+    assert!(is_retryable_fetch_error(&deno_core::error::custom_error(
+      "Http429",
+      "too many requests"
+    )));
+    assert!(is_retryable_fetch_error(&deno_core::error::custom_error(
+      "ConnectionReset",
+      "connection reset by peer"
+    )));
+
+    assert!(!is_retryable_fetch_error(&deno_core::error::custom_error(
+      "NotCached",
+      "not found in cache"
+    )));
+    assert!(!is_retryable_fetch_error(&deno_core::error::custom_error(
+      "IntegrityCheckFailed",
+      "bad hash"
+    )));
+    // errors of any other class, such as a plain generic error (e.g. a 404,
+    // an auth failure, or a local I/O error), are not retryable
+    assert!(!is_retryable_fetch_error(&deno_core::error::generic_error(
+      "not found"
+    )));
+  }
 }